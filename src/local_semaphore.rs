@@ -5,34 +5,151 @@
 //
 use futures::prelude::*;
 use futures::task::{Context, Poll, Waker};
-use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::cell::{RefCell, UnsafeCell};
 use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomPinned;
+use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
+use std::ptr::NonNull;
 use std::rc::Rc;
 
+fn closed_error() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "Semaphore Broken")
+}
+
+/// A node that can be linked into an [`IntrusiveList`].
+trait IntrusiveNode: Sized {
+    fn prev(&mut self) -> &mut Option<NonNull<Self>>;
+    fn next(&mut self) -> &mut Option<NonNull<Self>>;
+}
+
+/// A minimal intrusive doubly-linked list, shared by the semaphore's wait
+/// list and `Notify`'s wait list.
+///
+/// Nodes are linked in place -- typically living inline inside the future
+/// that owns them -- so pushing one never allocates. Linking and unlinking
+/// are `unsafe`: callers must ensure a pushed node outlives its time linked into
+/// the list (in this module, by pinning the future that embeds it) and that
+/// a node is linked into at most one list at a time.
+struct IntrusiveList<T: IntrusiveNode> {
+    head: Option<NonNull<T>>,
+    tail: Option<NonNull<T>>,
+}
+
+impl<T: IntrusiveNode> IntrusiveList<T> {
+    fn new() -> Self {
+        IntrusiveList {
+            head: None,
+            tail: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    // Safety: `node` must not already be linked into any list, and must
+    // outlive its time linked into this one.
+    unsafe fn push_back(&mut self, mut node: NonNull<T>) {
+        *node.as_mut().prev() = self.tail;
+        *node.as_mut().next() = None;
+        match self.tail {
+            Some(mut tail) => *tail.as_mut().next() = Some(node),
+            None => self.head = Some(node),
+        }
+        self.tail = Some(node);
+    }
+
+    // Safety: `node` must currently be linked into this list.
+    unsafe fn unlink(&mut self, mut node: NonNull<T>) {
+        let prev = *node.as_mut().prev();
+        let next = *node.as_mut().next();
+        match prev {
+            Some(mut prev) => *prev.as_mut().next() = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(mut next) => *next.as_mut().prev() = prev,
+            None => self.tail = prev,
+        }
+        *node.as_mut().prev() = None;
+        *node.as_mut().next() = None;
+    }
+
+    fn pop_front(&mut self) -> Option<NonNull<T>> {
+        let head = self.head?;
+        // Safety: `head` is, by definition, currently linked into this list.
+        unsafe { self.unlink(head) };
+        Some(head)
+    }
+}
+
+impl<T: IntrusiveNode> std::fmt::Debug for IntrusiveList<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IntrusiveList")
+            .field("head", &self.head)
+            .field("tail", &self.tail)
+            .finish()
+    }
+}
+
+/// The outcome a queued `Waiter` is resolved with once it leaves the
+/// intrusive wait list, either by being granted its units or by the
+/// semaphore being closed while it was still waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Waiting,
+    Granted,
+    Closed,
+}
+
+/// A node in `State`'s intrusively-linked wait list.
+///
+/// This struct lives inline inside the `Acquire` future that owns it, so
+/// linking it into the list does not require a separate allocation. Because
+/// neighbouring nodes hold raw pointers into it, it must never move while
+/// linked: `Acquire` is `!Unpin` and only links its waiter in after being
+/// polled through a `Pin`.
 struct Waiter {
     units: u64,
-    woken: bool,
+    outcome: Outcome,
     waker: Option<Waker>,
+    prev: Option<NonNull<Waiter>>,
+    next: Option<NonNull<Waiter>>,
 }
 
-impl Future for Waiter {
-    type Output = ();
+impl Waiter {
+    fn new(units: u64) -> Waiter {
+        Waiter {
+            units,
+            outcome: Outcome::Waiting,
+            waker: None,
+            prev: None,
+            next: None,
+        }
+    }
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.woken {
-            return Poll::Ready(());
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
         }
-        self.waker = Some(cx.waker().clone());
-        return Poll::Pending;
+    }
+}
+
+impl IntrusiveNode for Waiter {
+    fn prev(&mut self) -> &mut Option<NonNull<Waiter>> {
+        &mut self.prev
+    }
+
+    fn next(&mut self) -> &mut Option<NonNull<Waiter>> {
+        &mut self.next
     }
 }
 
 #[derive(Debug)]
 struct State {
     avail: u64,
-    list: VecDeque<*mut Waiter>,
+    list: IntrusiveList<Waiter>,
     closed: bool,
 }
 
@@ -40,7 +157,7 @@ impl State {
     fn new(avail: u64) -> Self {
         State {
             avail,
-            list: VecDeque::new(),
+            list: IntrusiveList::new(),
             closed: false,
         }
     }
@@ -49,69 +166,142 @@ impl State {
         self.avail
     }
 
-    fn queue(&mut self, units: u64) -> Box<Waiter> {
-        // FIXME: I should pin this
-        let mut waiter = Box::new(Waiter::new(units));
-        self.list.push_back(waiter.as_mut());
-        waiter
-    }
-
     fn try_acquire(&mut self, units: u64) -> Result<bool> {
-        if self.closed == true {
-            return Err(Error::new(ErrorKind::BrokenPipe, "Semaphore Broken"));
+        if self.closed {
+            return Err(closed_error());
         }
 
         if self.list.is_empty() && self.avail >= units {
             self.avail -= units;
             return Ok(true);
         }
-        return Ok(false);
+        Ok(false)
     }
 
     fn close(&mut self) {
         self.closed = true;
-        loop {
-            let cont = match self.list.pop_front() {
-                None => None,
-                Some(waitref) => {
-                    let waiter = unsafe { &mut *waitref };
-                    Some(waiter.wake())
-                }
-            };
-            if let None = cont {
-                break;
-            }
+        while let Some(mut node) = self.list.pop_front() {
+            let waiter = unsafe { node.as_mut() };
+            waiter.outcome = Outcome::Closed;
+            waiter.wake();
         }
     }
 
-    fn signal(&mut self, units: u64) -> Option<*mut Waiter> {
+    // Greedily hands units to waiters at the head of the line as long as
+    // there is enough `avail` to satisfy them, reserving each waiter's units
+    // (deducting from `avail`) before waking it. A waiter that doesn't fit
+    // stops the drain, so it isn't starved by smaller requests behind it:
+    // the units it needs stay reserved for it rather than being handed out
+    // of order.
+    fn signal(&mut self, units: u64) {
         self.avail += units;
 
-        if let Some(waitref) = self.list.front() {
-            let waiter = *waitref;
-            let w = unsafe { &mut *waiter };
-            if w.units <= self.avail {
-                self.list.pop_front();
-                return Some(waiter);
+        while let Some(node) = self.list.head {
+            let units_needed = unsafe { node.as_ref().units };
+            if units_needed > self.avail {
+                break;
             }
+            self.avail -= units_needed;
+            let mut node = self
+                .list
+                .pop_front()
+                .expect("head was just observed to be Some");
+            let waiter = unsafe { node.as_mut() };
+            waiter.outcome = Outcome::Granted;
+            waiter.wake();
         }
-        None
     }
 }
 
-impl Waiter {
-    fn wake(&mut self) {
-        if let Some(waker) = self.waker.take() {
-            self.woken = true;
-            waker.wake();
+/// The future returned by [`Semaphore::acquire`].
+///
+/// `Acquire` carries its wait-list node inline, so queuing a waiter never
+/// allocates. It is safe to drop an `Acquire` at any point -- including
+/// while it is parked in the semaphore's wait list under a `select!` or a
+/// timeout -- because dropping it unlinks the node from the list, or, if it
+/// had already been granted its units but not yet polled to completion,
+/// releases those units back to the semaphore instead of leaking them.
+pub struct Acquire {
+    state: Rc<RefCell<State>>,
+    units: u64,
+    waiter: Waiter,
+    queued: bool,
+    _pin: PhantomPinned,
+}
+
+impl Acquire {
+    fn new(state: Rc<RefCell<State>>, units: u64) -> Acquire {
+        Acquire {
+            state,
+            units,
+            waiter: Waiter::new(units),
+            queued: false,
+            _pin: PhantomPinned,
         }
     }
+}
 
-    fn new(units: u64) -> Waiter {
-        Waiter {
-            units,
-            woken: false,
-            waker: None,
+impl Future for Acquire {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `this` or any of its fields out; `waiter` is
+        // only ever accessed in place, and its address is only handed out as
+        // a `NonNull` while `self` stays pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        if this.queued {
+            return match this.waiter.outcome {
+                Outcome::Waiting => {
+                    this.waiter.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                Outcome::Granted => {
+                    this.queued = false;
+                    Poll::Ready(Ok(()))
+                }
+                Outcome::Closed => {
+                    this.queued = false;
+                    Poll::Ready(Err(closed_error()))
+                }
+            };
+        }
+
+        let mut state = this.state.borrow_mut();
+        match state.try_acquire(this.units) {
+            Err(e) => Poll::Ready(Err(e)),
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => {
+                this.waiter.waker = Some(cx.waker().clone());
+                let node = NonNull::from(&mut this.waiter);
+                // Safety: `node` is not currently linked into any list, and
+                // outlives its time in the list because `self` is pinned.
+                unsafe { state.list.push_back(node) };
+                this.queued = true;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if !self.queued {
+            return;
+        }
+        match self.waiter.outcome {
+            Outcome::Waiting => {
+                let node = NonNull::from(&mut self.waiter);
+                // Safety: the node is still linked, since we only reach here
+                // while `queued` is true and the outcome is still `Waiting`.
+                unsafe { self.state.borrow_mut().list.unlink(node) };
+            }
+            Outcome::Granted => {
+                // We were woken with our units reserved, but never got a
+                // chance to hand them to the caller. Give them back.
+                self.state.borrow_mut().signal(self.waiter.units);
+            }
+            Outcome::Closed => {}
         }
     }
 }
@@ -119,7 +309,11 @@ impl Waiter {
 /// The permit is A RAII-friendly way to acquire semaphore resources.
 ///
 /// Resources are held while the Permit is alive, and released when the
-/// permit is dropped.
+/// permit is dropped. It holds its own `Rc` clone of the semaphore's state
+/// rather than borrowing the `Semaphore`, so it has no lifetime tied to the
+/// handle it came from: [`Semaphore::acquire_owned`] and
+/// [`Semaphore::try_acquire_owned`] rely on exactly this to return permits
+/// that can be moved into a spawned task or stored in a `'static` struct.
 #[derive(Debug)]
 pub struct Permit {
     units: u64,
@@ -128,20 +322,13 @@ pub struct Permit {
 
 impl Permit {
     fn new(units: u64, sem: Rc<RefCell<State>>) -> Permit {
-        Permit {
-            units,
-            sem: sem.clone(),
-        }
+        Permit { units, sem }
     }
 }
 
 impl Drop for Permit {
     fn drop(&mut self) {
-        let waker = self.sem.borrow_mut().signal(self.units);
-        waker.and_then(|w| {
-            let waiter = unsafe { &mut *w };
-            Some(waiter.wake())
-        });
+        self.sem.borrow_mut().signal(self.units);
     }
 }
 
@@ -177,16 +364,62 @@ impl Semaphore {
     ///
     /// The caller is then responsible to release it. Whenever possible,
     /// prefer acquire_permit().
-    pub async fn acquire(&self, units: u64) -> Result<()> {
-        loop {
-            let mut state = self.state.borrow_mut();
-            if state.try_acquire(units)? {
-                return Ok(());
-            }
+    ///
+    /// The returned future is safe to drop at any point, including while it
+    /// is still queued: doing so unlinks it from the semaphore's wait list
+    /// instead of corrupting it.
+    pub fn acquire(&self, units: u64) -> Acquire {
+        Acquire::new(self.state.clone(), units)
+    }
 
-            let waiter = state.queue(units);
-            drop(state);
-            waiter.await;
+    /// Tries to acquire a permit for the specified amount of units without
+    /// waiting.
+    ///
+    /// Returns `Ok(None)` if the units are not immediately available, and
+    /// `Err()` if the semaphore is closed.
+    pub fn try_acquire_permit(&self, units: u64) -> Result<Option<Permit>> {
+        let mut state = self.state.borrow_mut();
+        if state.try_acquire(units)? {
+            Ok(Some(Permit::new(units, self.state.clone())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Blocks until a permit can be acquired with the specified amount of
+    /// units, returning a permit that owns its own handle to the semaphore.
+    ///
+    /// Unlike [`acquire_permit`](Semaphore::acquire_permit), this takes the
+    /// semaphore by its `Rc` rather than by reference, so the returned future
+    /// borrows nothing either. The resulting [`Permit`], like every `Permit`,
+    /// has no lifetime tied to the caller's `Semaphore` handle and
+    /// can be moved into a spawned task or stored in a `'static` struct, e.g.
+    /// to implement a concurrency limiter that attaches a permit to each
+    /// in-flight operation:
+    ///
+    /// ```ignore
+    /// let permit = Rc::clone(&sem).acquire_owned(1).await?;
+    /// spawn_local(async move {
+    ///     let _permit = permit; // dropped when this task completes
+    ///     do_work().await
+    /// });
+    /// ```
+    pub async fn acquire_owned(self: Rc<Self>, units: u64) -> Result<Permit> {
+        self.acquire(units).await?;
+        Ok(Permit::new(units, self.state.clone()))
+    }
+
+    /// Tries to acquire an owned permit for the specified amount of units
+    /// without waiting. See [`acquire_owned`](Semaphore::acquire_owned).
+    ///
+    /// Returns `Ok(None)` if the units are not immediately available, and
+    /// `Err()` if the semaphore is closed.
+    pub fn try_acquire_owned(self: Rc<Self>, units: u64) -> Result<Option<Permit>> {
+        let mut state = self.state.borrow_mut();
+        if state.try_acquire(units)? {
+            Ok(Some(Permit::new(units, self.state.clone())))
+        } else {
+            Ok(None)
         }
     }
 
@@ -195,18 +428,610 @@ impl Semaphore {
     /// This needs to be paired with a call to acquire(). You should not
     /// call this if the units were acquired with acquire_permit().
     pub fn signal(&self, units: u64) {
-        let waker = self.state.borrow_mut().signal(units);
-        waker.and_then(|w| {
-            let waiter = unsafe { &mut *w };
-            Some(waiter.wake())
-        });
+        self.state.borrow_mut().signal(units);
     }
 
     /// Closes the semaphore
     ///
     /// All existing waiters will return Err(), and no new waiters are allowed.
     pub fn close(&self) {
-        let mut state = self.state.borrow_mut();
-        state.close();
+        self.state.borrow_mut().close();
+    }
+}
+
+// Large enough that it is never exhausted by readers alone, while still
+// leaving room for a writer to claim the whole batch at once.
+const MAX_READERS: u64 = u32::MAX as u64;
+
+/// A single-threaded, reader-writer lock over a value of type `T`.
+///
+/// Built on top of [`Semaphore`] the same way Tokio layers its `RwLock` on a
+/// batch semaphore: a reader acquires one unit, while a writer acquires all
+/// `MAX_READERS` units at once. Because the underlying semaphore is fair and
+/// hands out units FIFO, a writer queued behind a run of readers is not
+/// starved: once it reaches the head of the line, no further readers are
+/// admitted until it has acquired (and released) the full batch.
+#[derive(Debug)]
+pub struct RwLock<T> {
+    sem: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new `RwLock` protecting the given value.
+    pub fn new(value: T) -> RwLock<T> {
+        RwLock {
+            sem: Semaphore::new(MAX_READERS),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Locks this `RwLock` for read access, blocking until it is available.
+    ///
+    /// Returns `Err()` if the lock is closed during the wait.
+    pub async fn read(&self) -> Result<RwLockReadGuard<'_, T>> {
+        let permit = self.sem.acquire_permit(1).await?;
+        Ok(RwLockReadGuard { lock: self, permit })
+    }
+
+    /// Locks this `RwLock` for write access, blocking until it is available.
+    ///
+    /// Returns `Err()` if the lock is closed during the wait.
+    pub async fn write(&self) -> Result<RwLockWriteGuard<'_, T>> {
+        let permit = self.sem.acquire_permit(MAX_READERS).await?;
+        Ok(RwLockWriteGuard { lock: self, permit })
+    }
+
+    /// Tries to lock this `RwLock` for read access without waiting.
+    ///
+    /// Returns `Err(WouldBlock)` if it is currently locked for writing or a
+    /// writer is waiting for it, and `Err(BrokenPipe)` if the lock is closed.
+    pub fn try_read(&self) -> Result<RwLockReadGuard<'_, T>> {
+        match self.sem.try_acquire_permit(1)? {
+            Some(permit) => Ok(RwLockReadGuard { lock: self, permit }),
+            None => Err(Error::new(
+                ErrorKind::WouldBlock,
+                "RwLock is locked for writing",
+            )),
+        }
+    }
+
+    /// Tries to lock this `RwLock` for write access without waiting.
+    ///
+    /// Returns `Err(WouldBlock)` if it is currently locked, and
+    /// `Err(BrokenPipe)` if the lock is closed.
+    pub fn try_write(&self) -> Result<RwLockWriteGuard<'_, T>> {
+        match self.sem.try_acquire_permit(MAX_READERS)? {
+            Some(permit) => Ok(RwLockWriteGuard { lock: self, permit }),
+            None => Err(Error::new(ErrorKind::WouldBlock, "RwLock is locked")),
+        }
+    }
+
+    /// Closes the lock.
+    ///
+    /// All existing and future waiters will return `Err()` rather than ever
+    /// gaining access to the value.
+    pub fn close(&self) {
+        self.sem.close();
+    }
+}
+
+/// RAII structure used to release the shared read access of a [`RwLock`]
+/// when dropped.
+///
+/// This is returned by [`RwLock::read`] and [`RwLock::try_read`].
+#[derive(Debug)]
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    // Only ever read by `Drop`, which releases the unit(s) back to the semaphore.
+    #[allow(dead_code)]
+    permit: Permit,
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: holding the permit guarantees no writer can be holding the
+        // full batch of units at the same time.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+/// RAII structure used to release the exclusive write access of a
+/// [`RwLock`] when dropped.
+///
+/// This is returned by [`RwLock::write`] and [`RwLock::try_write`].
+#[derive(Debug)]
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    // Only ever read by `Drop`, which releases the unit(s) back to the semaphore.
+    #[allow(dead_code)]
+    permit: Permit,
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: holding all `MAX_READERS` units guarantees no other reader
+        // or writer can be accessing the value at the same time.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: see `Deref` above.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+/// A node in `NotifyState`'s intrusively-linked wait list.
+///
+/// Lives inline inside the `Notified` future that owns it, following the
+/// same self-unlinking-on-drop design as the semaphore's `Waiter`.
+struct NotifyWaiter {
+    notified: bool,
+    // Whether the wakeup came from `notify_one()` rather than `notify_all()`.
+    by_notify_one: bool,
+    waker: Option<Waker>,
+    prev: Option<NonNull<NotifyWaiter>>,
+    next: Option<NonNull<NotifyWaiter>>,
+}
+
+impl NotifyWaiter {
+    fn new() -> NotifyWaiter {
+        NotifyWaiter {
+            notified: false,
+            by_notify_one: false,
+            waker: None,
+            prev: None,
+            next: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl IntrusiveNode for NotifyWaiter {
+    fn prev(&mut self) -> &mut Option<NonNull<NotifyWaiter>> {
+        &mut self.prev
+    }
+
+    fn next(&mut self) -> &mut Option<NonNull<NotifyWaiter>> {
+        &mut self.next
+    }
+}
+
+#[derive(Debug)]
+struct NotifyState {
+    list: IntrusiveList<NotifyWaiter>,
+    // A single stored wakeup, delivered to the next call to `notified()`
+    // when no task is currently waiting. At most one is ever stored.
+    pending: bool,
+}
+
+impl NotifyState {
+    fn new() -> Self {
+        NotifyState {
+            list: IntrusiveList::new(),
+            pending: false,
+        }
+    }
+
+    fn take_pending(&mut self) -> bool {
+        std::mem::replace(&mut self.pending, false)
+    }
+
+    fn notify_one(&mut self) {
+        match self.list.pop_front() {
+            Some(mut node) => {
+                let waiter = unsafe { node.as_mut() };
+                waiter.notified = true;
+                waiter.by_notify_one = true;
+                waiter.wake();
+            }
+            None => self.pending = true,
+        }
+    }
+
+    fn notify_all(&mut self) {
+        while let Some(mut node) = self.list.pop_front() {
+            let waiter = unsafe { node.as_mut() };
+            waiter.notified = true;
+            waiter.wake();
+        }
+    }
+}
+
+/// The future returned by [`Notify::notified`].
+///
+/// Safe to drop at any point: if it is still queued and hasn't been woken
+/// yet, dropping it unlinks its node from the wait list. If `notify_one()`
+/// had already woken it but the future is dropped before it is polled to
+/// completion, the wakeup is preserved as a stored notification instead of
+/// being lost.
+pub struct Notified {
+    state: Rc<RefCell<NotifyState>>,
+    waiter: NotifyWaiter,
+    queued: bool,
+    _pin: PhantomPinned,
+}
+
+impl Notified {
+    fn new(state: Rc<RefCell<NotifyState>>) -> Notified {
+        Notified {
+            state,
+            waiter: NotifyWaiter::new(),
+            queued: false,
+            _pin: PhantomPinned,
+        }
+    }
+}
+
+impl Future for Notified {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: see `Acquire::poll` above; the same invariants apply.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        if this.queued {
+            if this.waiter.notified {
+                this.queued = false;
+                return Poll::Ready(());
+            }
+            this.waiter.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let mut state = this.state.borrow_mut();
+        if state.take_pending() {
+            return Poll::Ready(());
+        }
+        this.waiter.waker = Some(cx.waker().clone());
+        let node = NonNull::from(&mut this.waiter);
+        // Safety: `node` is not currently linked into any list, and
+        // outlives its time in the list because `self` is pinned.
+        unsafe { state.list.push_back(node) };
+        this.queued = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified {
+    fn drop(&mut self) {
+        if !self.queued {
+            return;
+        }
+        if self.waiter.notified {
+            // notify_one() already handed us the wakeup; since we never
+            // consumed it, store it back so it isn't lost. A wakeup from
+            // notify_all() is not stored.
+            if self.waiter.by_notify_one {
+                self.state.borrow_mut().pending = true;
+            }
+        } else {
+            let node = NonNull::from(&mut self.waiter);
+            // Safety: the node is still linked, since we only reach here
+            // while `queued` is true and it hasn't been notified yet.
+            unsafe { self.state.borrow_mut().list.unlink(node) };
+        }
+    }
+}
+
+/// A single-threaded task-notification primitive, for the common
+/// park/unpark pattern that a raw counting [`Semaphore`] models awkwardly.
+///
+/// `notify_one()` wakes exactly one waiting task. If no task is currently
+/// waiting, it stores a single pending notification so that the *next*
+/// call to [`notified`](Notify::notified) returns immediately -- at most
+/// one notification is ever stored, it is not counted. `notify_all()`
+/// wakes every current waiter without leaving a stored notification.
+///
+/// This is a building block for condition-variable-style signaling, and
+/// for implementing bounded channels without abusing
+/// [`Semaphore::signal`]/[`Semaphore::acquire`].
+#[derive(Debug)]
+pub struct Notify {
+    state: Rc<RefCell<NotifyState>>,
+}
+
+impl Notify {
+    /// Creates a new `Notify`, with no task currently waiting and no
+    /// pending notification stored.
+    pub fn new() -> Notify {
+        Notify {
+            state: Rc::new(RefCell::new(NotifyState::new())),
+        }
+    }
+
+    /// Waits for a notification.
+    ///
+    /// Returns immediately if a notification was already stored by a
+    /// previous call to [`notify_one`](Notify::notify_one) that had no
+    /// waiter to deliver it to. The returned future is safe to drop at any
+    /// point, including while still queued.
+    pub fn notified(&self) -> Notified {
+        Notified::new(self.state.clone())
+    }
+
+    /// Wakes one waiting task, if any. If no task is currently waiting,
+    /// stores a single notification so the next call to
+    /// [`notified`](Notify::notified) completes immediately.
+    pub fn notify_one(&self) {
+        self.state.borrow_mut().notify_one();
+    }
+
+    /// Wakes every task currently waiting on this `Notify`, without storing
+    /// a notification for calls to [`notified`](Notify::notified) that
+    /// happen afterwards.
+    pub fn notify_all(&self) {
+        self.state.borrow_mut().notify_all();
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Notify {
+        Notify::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use std::io::ErrorKind;
+
+    fn poll_once(fut: Pin<&mut Acquire>) -> Poll<Result<()>> {
+        let waker = noop_waker_ref();
+        let mut cx = Context::from_waker(waker);
+        fut.poll(&mut cx)
+    }
+
+    fn poll_notified_once(fut: Pin<&mut Notified>) -> Poll<()> {
+        let waker = noop_waker_ref();
+        let mut cx = Context::from_waker(waker);
+        fut.poll(&mut cx)
+    }
+
+    #[test]
+    fn drop_while_queued_unlinks_and_does_not_corrupt_state() {
+        let sem = Semaphore::new(0);
+        {
+            let mut fut = Box::pin(sem.acquire(1));
+            assert!(poll_once(fut.as_mut()).is_pending());
+            // `fut` is dropped here while still linked into the wait list.
+        }
+
+        // A dangling node left behind would make either of these corrupt
+        // memory or panic; with the node unlinked on drop, both just work.
+        sem.signal(1);
+        assert_eq!(sem.available(), 1);
+        sem.close();
+    }
+
+    #[test]
+    fn drop_after_granted_but_before_ready_returns_units() {
+        let sem = Semaphore::new(1);
+        let permit = futures::executor::block_on(sem.acquire_permit(1)).unwrap();
+
+        {
+            let mut fut = Box::pin(sem.acquire(1));
+            assert!(poll_once(fut.as_mut()).is_pending());
+
+            // Releasing the only permit reserves its unit for the queued
+            // waiter above and wakes it, without resuming it (the waker is
+            // a no-op), so it never gets to observe `Poll::Ready(Ok(()))`.
+            drop(permit);
+            assert_eq!(sem.available(), 0);
+
+            // `fut` is dropped here still holding the granted-but-unclaimed
+            // unit.
+        }
+
+        assert_eq!(sem.available(), 1);
+    }
+
+    #[test]
+    fn signal_satisfies_first_waiter_and_leaves_larger_second_waiter_queued() {
+        let sem = Semaphore::new(0);
+        let mut first = Box::pin(sem.acquire(1));
+        let mut second = Box::pin(sem.acquire(5));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert!(poll_once(second.as_mut()).is_pending());
+
+        // Only enough units for `first`; `second` needs 5 and must stay
+        // queued at the head of the line rather than being skipped over so
+        // a later, smaller waiter could jump ahead of it.
+        sem.signal(2);
+
+        assert!(matches!(poll_once(first.as_mut()), Poll::Ready(Ok(()))));
+        assert!(poll_once(second.as_mut()).is_pending());
+        assert_eq!(sem.available(), 1);
+
+        // The leftover unit stays reserved for `second`; once enough units
+        // arrive, it's granted without `first` being re-queued behind it.
+        sem.signal(4);
+        assert!(matches!(poll_once(second.as_mut()), Poll::Ready(Ok(()))));
+    }
+
+    #[test]
+    fn close_wakes_all_queued_waiters_with_err() {
+        let sem = Semaphore::new(0);
+        let mut fut1 = Box::pin(sem.acquire(1));
+        let mut fut2 = Box::pin(sem.acquire(1));
+        assert!(poll_once(fut1.as_mut()).is_pending());
+        assert!(poll_once(fut2.as_mut()).is_pending());
+
+        sem.close();
+
+        for fut in [fut1.as_mut(), fut2.as_mut()] {
+            match poll_once(fut) {
+                Poll::Ready(Err(e)) => assert_eq!(e.kind(), ErrorKind::BrokenPipe),
+                other => panic!("expected Poll::Ready(Err(..)), got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn notify_drop_while_queued_unlinks_and_does_not_corrupt_state() {
+        let notify = Notify::new();
+        {
+            let mut fut = Box::pin(notify.notified());
+            assert!(poll_notified_once(fut.as_mut()).is_pending());
+            // `fut` is dropped here while still linked into the wait list.
+        }
+
+        // A dangling node left behind would make this corrupt memory or
+        // panic; with the node unlinked on drop, there's no queued waiter
+        // left, so this call stores a pending notification instead.
+        notify.notify_one();
+        let mut fut = Box::pin(notify.notified());
+        assert_eq!(poll_notified_once(fut.as_mut()), Poll::Ready(()));
+    }
+
+    #[test]
+    fn notify_drop_after_notified_but_before_ready_preserves_notification() {
+        let notify = Notify::new();
+        {
+            let mut fut = Box::pin(notify.notified());
+            assert!(poll_notified_once(fut.as_mut()).is_pending());
+
+            // Wakes the queued waiter above but doesn't resume it (the
+            // waker is a no-op), so it never gets to observe
+            // `Poll::Ready(())`.
+            notify.notify_one();
+
+            // `fut` is dropped here still holding the unconsumed wakeup.
+        }
+
+        // The wakeup must be preserved rather than lost, so the next
+        // `notified()` call completes immediately.
+        let mut fut = Box::pin(notify.notified());
+        assert_eq!(poll_notified_once(fut.as_mut()), Poll::Ready(()));
+    }
+
+    #[test]
+    fn notify_all_wakes_every_queued_waiter() {
+        let notify = Notify::new();
+        let mut fut1 = Box::pin(notify.notified());
+        let mut fut2 = Box::pin(notify.notified());
+        assert!(poll_notified_once(fut1.as_mut()).is_pending());
+        assert!(poll_notified_once(fut2.as_mut()).is_pending());
+
+        notify.notify_all();
+
+        assert_eq!(poll_notified_once(fut1.as_mut()), Poll::Ready(()));
+        assert_eq!(poll_notified_once(fut2.as_mut()), Poll::Ready(()));
+    }
+
+    #[test]
+    fn owned_permit_outlives_the_handle_it_was_acquired_through() {
+        let sem = Rc::new(Semaphore::new(1));
+        let inspector = Rc::clone(&sem);
+        let permit = futures::executor::block_on(Rc::clone(&sem).acquire_owned(1)).unwrap();
+        assert_eq!(inspector.available(), 0);
+
+        // Drop the handle the permit was acquired through -- the permit
+        // holds its own `Rc` clone of the semaphore's state, so this must
+        // not affect it.
+        drop(sem);
+
+        // Simulates moving the permit into a spawned `'static` task: the
+        // unit is released when the task (here, this scope) completes,
+        // independent of the original handle's lifetime.
+        {
+            let _permit = permit;
+        }
+
+        assert_eq!(inspector.available(), 1);
+    }
+
+    #[test]
+    fn try_acquire_owned_succeeds_and_releases_independently_of_the_handle() {
+        let sem = Rc::new(Semaphore::new(1));
+        let permit = Rc::clone(&sem)
+            .try_acquire_owned(1)
+            .unwrap()
+            .expect("unit should be immediately available");
+        assert_eq!(sem.available(), 0);
+
+        drop(permit);
+        assert_eq!(sem.available(), 1);
+    }
+
+    fn poll_fut<F: Future>(fut: Pin<&mut F>) -> Poll<F::Output> {
+        let waker = noop_waker_ref();
+        let mut cx = Context::from_waker(waker);
+        fut.poll(&mut cx)
+    }
+
+    #[test]
+    fn write_is_not_starved_by_readers_queued_behind_it() {
+        let lock = RwLock::new(0);
+
+        let mut r1 = Box::pin(lock.read());
+        let guard1 = match poll_fut(r1.as_mut()) {
+            Poll::Ready(Ok(g)) => g,
+            other => panic!("expected Poll::Ready(Ok(..)), got {:?}", other),
+        };
+        let mut r2 = Box::pin(lock.read());
+        let guard2 = match poll_fut(r2.as_mut()) {
+            Poll::Ready(Ok(g)) => g,
+            other => panic!("expected Poll::Ready(Ok(..)), got {:?}", other),
+        };
+
+        // The writer can't fit alongside the two live readers, so it queues.
+        let mut w = Box::pin(lock.write());
+        assert!(poll_fut(w.as_mut()).is_pending());
+
+        // A reader arriving after the queued writer must wait behind it,
+        // even though there are units free -- jumping the line would starve
+        // the writer indefinitely under a steady stream of readers.
+        let mut r3 = Box::pin(lock.read());
+        assert!(poll_fut(r3.as_mut()).is_pending());
+
+        drop(guard1);
+        drop(guard2);
+
+        let guard3 = match poll_fut(w.as_mut()) {
+            Poll::Ready(Ok(g)) => g,
+            other => panic!("expected Poll::Ready(Ok(..)), got {:?}", other),
+        };
+        // `r3` still must not have been let through ahead of the writer.
+        assert!(poll_fut(r3.as_mut()).is_pending());
+
+        drop(guard3);
+        match poll_fut(r3.as_mut()) {
+            Poll::Ready(Ok(_)) => {}
+            other => panic!("expected Poll::Ready(Ok(..)), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reader_proceeds_once_writer_releases_the_lock() {
+        let lock = RwLock::new(0);
+
+        let mut w = Box::pin(lock.write());
+        let guard = match poll_fut(w.as_mut()) {
+            Poll::Ready(Ok(g)) => g,
+            other => panic!("expected Poll::Ready(Ok(..)), got {:?}", other),
+        };
+
+        let mut r = Box::pin(lock.read());
+        assert!(poll_fut(r.as_mut()).is_pending());
+
+        drop(guard);
+
+        match poll_fut(r.as_mut()) {
+            Poll::Ready(Ok(_)) => {}
+            other => panic!("expected Poll::Ready(Ok(..)), got {:?}", other),
+        }
     }
 }